@@ -0,0 +1,419 @@
+use crate::Error;
+use memmap2::{MmapMut, MmapOptions};
+use std::{
+    fs::{File, OpenOptions},
+    path::{Path, PathBuf},
+    sync::RwLock,
+};
+
+// "DPBKIDX1" as a little-endian u64, used to recognise a valid index file and
+// to reject files written by an incompatible layout.
+const MAGIC: u64 = 0x31_58_44_49_4B_42_50_44;
+
+const HEADER_SIZE: usize = 16;
+const SLOT_SIZE: usize = 16;
+const SLOTS_PER_BUCKET: usize = 8;
+const BUCKET_SIZE: usize = SLOTS_PER_BUCKET * SLOT_SIZE;
+const INITIAL_K: u32 = 8;
+const MAX_LOAD_FACTOR: f64 = 0.75;
+
+const EMPTY_HASH: u64 = 0;
+
+/// An mmap-backed, open-addressed bucket map from key hash to flatfile
+/// offset, persisted as a third file alongside `data` and `seqno`.
+///
+/// The map is organised as `2^k` fixed-size buckets of `SLOTS_PER_BUCKET`
+/// slots. A key is routed to bucket `hash & (2^k - 1)`; within a bucket,
+/// insertion and lookup linear-probe the slots. Callers are expected to
+/// confirm a hit by comparing the full key against the record stored at the
+/// returned offset, since distinct keys can share a hash.
+///
+/// `map_size` reserves a large virtual mmap up front (mirroring
+/// [`crate::flatfile::FlatFile`]); only the header and the buckets actually
+/// in use are ever written to. The backing `file` is kept open (rather than
+/// just mapped) because `grow` must `set_len` it out to the new bucket
+/// count's on-disk footprint before writing into the freshly-exposed mmap
+/// pages — writing into mapped-but-unbacked pages raises SIGBUS, which is
+/// not a catchable Rust panic.
+pub(crate) struct BucketIndex {
+    file: File,
+    mmap: RwLock<MmapMut>,
+    path: PathBuf,
+    map_size: usize,
+    // Whether the file already held a valid, populated table when it was
+    // opened, as opposed to having just been created from scratch.
+    preexisting: bool,
+}
+
+/// Fast, non-cryptographic FNV-1a hash. Good enough to distribute keys
+/// across buckets; full key equality is always re-checked against the
+/// flatfile record before trusting a match.
+fn hash_key(key: &[u8]) -> u64 {
+    const OFFSET_BASIS: u64 = 0xcbf2_9ce4_8422_2325;
+    const PRIME: u64 = 0x0000_0100_0000_01b3;
+
+    let mut hash = OFFSET_BASIS;
+    for &byte in key {
+        hash ^= byte as u64;
+        hash = hash.wrapping_mul(PRIME);
+    }
+    // A hash of exactly zero would be indistinguishable from an empty slot.
+    if hash == EMPTY_HASH {
+        1
+    } else {
+        hash
+    }
+}
+
+fn bucket_offset(bucket: u64) -> usize {
+    HEADER_SIZE + (bucket as usize) * BUCKET_SIZE
+}
+
+impl BucketIndex {
+    /// Open an existing index file, or create a fresh (empty) one if it
+    /// doesn't exist yet.
+    pub fn new<P: AsRef<Path>>(path: P, map_size: usize) -> Result<Self, Error> {
+        let path = path.as_ref().to_path_buf();
+        let is_new = !path.exists();
+
+        let file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .open(&path)
+            .map_err(|err| Error::FileOpen(path.clone(), err))?;
+
+        let min_len = HEADER_SIZE + (1usize << INITIAL_K) * BUCKET_SIZE;
+        if map_size < min_len {
+            return Err(Error::BucketIndexFull {
+                path,
+                required: min_len,
+                capacity: map_size,
+            });
+        }
+        if is_new || file.metadata().map(|m| m.len() as usize).unwrap_or(0) < min_len {
+            file.set_len(min_len as u64)
+                .map_err(|err| Error::FileOpen(path.clone(), err))?;
+        }
+
+        let mmap = unsafe {
+            MmapOptions::new()
+                .len(map_size)
+                .map_mut(&file)
+                .map_err(|err| Error::FileOpen(path.clone(), err))?
+        };
+
+        let mut index = Self {
+            file,
+            mmap: RwLock::new(mmap),
+            path,
+            map_size,
+            preexisting: false,
+        };
+
+        if is_new {
+            index.init_header(INITIAL_K);
+        } else if index.read_magic() != MAGIC {
+            if index.read_magic() == 0 {
+                index.init_header(INITIAL_K);
+            } else {
+                return Err(Error::InvalidIndex(index.path.clone()));
+            }
+        } else {
+            index.preexisting = true;
+        }
+
+        Ok(index)
+    }
+
+    /// Whether this index already held a complete table when opened, i.e.
+    /// `entries` can be trusted instead of rebuilding from the flatfile.
+    pub fn is_preexisting(&self) -> bool {
+        self.preexisting
+    }
+
+    /// Every occupied `(key_hash, offset)` slot across all buckets.
+    pub fn entries(&self) -> Vec<(u64, u64)> {
+        let mmap = self.mmap.read().unwrap();
+        let k = u32::from_le_bytes(mmap[8..12].try_into().unwrap());
+        let bucket_count = 1u64 << k;
+
+        let mut entries = Vec::new();
+        for bucket in 0..bucket_count {
+            for slot in 0..SLOTS_PER_BUCKET {
+                let (hash, value) = Self::read_slot(&mmap, bucket, slot);
+                if hash != EMPTY_HASH {
+                    entries.push((hash, value));
+                }
+            }
+        }
+        entries
+    }
+
+    fn init_header(&self, k: u32) {
+        let mut mmap = self.mmap.write().unwrap();
+        mmap[0..8].copy_from_slice(&MAGIC.to_le_bytes());
+        mmap[8..12].copy_from_slice(&k.to_le_bytes());
+        mmap[12..16].copy_from_slice(&0u32.to_le_bytes());
+    }
+
+    fn read_magic(&self) -> u64 {
+        let mmap = self.mmap.read().unwrap();
+        u64::from_le_bytes(mmap[0..8].try_into().unwrap())
+    }
+
+    fn read_k(&self) -> u32 {
+        let mmap = self.mmap.read().unwrap();
+        u32::from_le_bytes(mmap[8..12].try_into().unwrap())
+    }
+
+    fn write_k(mmap: &mut MmapMut, k: u32) {
+        mmap[8..12].copy_from_slice(&k.to_le_bytes());
+    }
+
+    fn read_slot(mmap: &MmapMut, bucket: u64, slot: usize) -> (u64, u64) {
+        let offset = bucket_offset(bucket) + slot * SLOT_SIZE;
+        let hash = u64::from_le_bytes(mmap[offset..offset + 8].try_into().unwrap());
+        let value = u64::from_le_bytes(mmap[offset + 8..offset + 16].try_into().unwrap());
+        (hash, value)
+    }
+
+    fn write_slot(mmap: &mut MmapMut, bucket: u64, slot: usize, hash: u64, value: u64) {
+        let offset = bucket_offset(bucket) + slot * SLOT_SIZE;
+        mmap[offset..offset + 8].copy_from_slice(&hash.to_le_bytes());
+        mmap[offset + 8..offset + 16].copy_from_slice(&value.to_le_bytes());
+    }
+
+    /// Insert (or overwrite) the offset for a key's hash.
+    pub fn insert(&self, key: &[u8], offset: usize) -> Result<(), Error> {
+        let hash = hash_key(key);
+
+        loop {
+            let k = self.read_k();
+            let bucket = hash & ((1u64 << k) - 1);
+
+            if self.try_insert_into_bucket(bucket, hash, offset as u64) {
+                return Ok(());
+            }
+
+            // The target bucket is full: grow the table and retry.
+            self.grow()?;
+        }
+    }
+
+    fn try_insert_into_bucket(&self, bucket: u64, hash: u64, value: u64) -> bool {
+        let mut mmap = self.mmap.write().unwrap();
+        let mut free_slot = None;
+
+        for slot in 0..SLOTS_PER_BUCKET {
+            let (slot_hash, _) = Self::read_slot(&mmap, bucket, slot);
+            if slot_hash == hash {
+                Self::write_slot(&mut mmap, bucket, slot, hash, value);
+                return true;
+            }
+            if slot_hash == EMPTY_HASH && free_slot.is_none() {
+                free_slot = Some(slot);
+            }
+        }
+
+        let occupied = (0..SLOTS_PER_BUCKET)
+            .filter(|&slot| Self::read_slot(&mmap, bucket, slot).0 != EMPTY_HASH)
+            .count();
+        if (occupied as f64 + 1.0) / SLOTS_PER_BUCKET as f64 > MAX_LOAD_FACTOR {
+            return false;
+        }
+
+        match free_slot {
+            Some(slot) => {
+                Self::write_slot(&mut mmap, bucket, slot, hash, value);
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Double the number of buckets and redistribute every live entry. The
+    /// new high bit of the hash decides whether an entry stays in bucket `b`
+    /// or moves to its split sibling `b | (1 << old_k)`.
+    fn grow(&self) -> Result<(), Error> {
+        let mut mmap = self.mmap.write().unwrap();
+        let old_k = u32::from_le_bytes(mmap[8..12].try_into().unwrap());
+        let new_k = old_k + 1;
+        let old_bucket_count = 1u64 << old_k;
+
+        let required_len = HEADER_SIZE + (1usize << new_k) * BUCKET_SIZE;
+        if required_len > self.map_size {
+            return Err(Error::BucketIndexFull {
+                path: self.path.clone(),
+                required: required_len,
+                capacity: self.map_size,
+            });
+        }
+
+        // The mmap only ever reserves `map_size` bytes of address space; the
+        // file backing it must be grown to match before buckets beyond its
+        // current on-disk length are written, or the write raises SIGBUS
+        // instead of returning an error.
+        if required_len as u64 > self.file.metadata().map(|m| m.len()).unwrap_or(0) {
+            self.file
+                .set_len(required_len as u64)
+                .map_err(|err| Error::FileOpen(self.path.clone(), err))?;
+        }
+
+        // Clear the newly-exposed (split sibling) buckets before
+        // redistributing, since they may contain stale bytes.
+        for bucket in old_bucket_count..(old_bucket_count * 2) {
+            for slot in 0..SLOTS_PER_BUCKET {
+                Self::write_slot(&mut mmap, bucket, slot, EMPTY_HASH, 0);
+            }
+        }
+
+        for bucket in 0..old_bucket_count {
+            let entries: Vec<(u64, u64)> = (0..SLOTS_PER_BUCKET)
+                .map(|slot| Self::read_slot(&mmap, bucket, slot))
+                .filter(|&(hash, _)| hash != EMPTY_HASH)
+                .collect();
+
+            for slot in 0..SLOTS_PER_BUCKET {
+                Self::write_slot(&mut mmap, bucket, slot, EMPTY_HASH, 0);
+            }
+
+            for (hash, value) in entries {
+                let target_bucket = hash & ((1u64 << new_k) - 1);
+                for slot in 0..SLOTS_PER_BUCKET {
+                    let (slot_hash, _) = Self::read_slot(&mmap, target_bucket, slot);
+                    if slot_hash == EMPTY_HASH {
+                        Self::write_slot(&mut mmap, target_bucket, slot, hash, value);
+                        break;
+                    }
+                }
+            }
+        }
+
+        Self::write_k(&mut mmap, new_k);
+        Ok(())
+    }
+
+    /// Return every offset stored for `key`'s hash. The caller must
+    /// disambiguate collisions by reading the candidate records.
+    pub fn lookup(&self, key: &[u8]) -> Vec<usize> {
+        let hash = hash_key(key);
+        let mmap = self.mmap.read().unwrap();
+        let k = u32::from_le_bytes(mmap[8..12].try_into().unwrap());
+        let bucket = hash & ((1u64 << k) - 1);
+
+        (0..SLOTS_PER_BUCKET)
+            .filter_map(|slot| {
+                let (slot_hash, value) = Self::read_slot(&mmap, bucket, slot);
+                (slot_hash == hash).then_some(value as usize)
+            })
+            .collect()
+    }
+
+    /// Remove the slot holding `offset` for `key`, if present.
+    pub fn remove(&self, key: &[u8], offset: usize) {
+        let hash = hash_key(key);
+        let mut mmap = self.mmap.write().unwrap();
+        let k = u32::from_le_bytes(mmap[8..12].try_into().unwrap());
+        let bucket = hash & ((1u64 << k) - 1);
+
+        for slot in 0..SLOTS_PER_BUCKET {
+            let (slot_hash, value) = Self::read_slot(&mmap, bucket, slot);
+            if slot_hash == hash && value as usize == offset {
+                Self::write_slot(&mut mmap, bucket, slot, EMPTY_HASH, 0);
+                return;
+            }
+        }
+    }
+
+    /// Wipe every entry and reset the table back to its initial size.
+    pub fn clear(&self) {
+        let mut mmap = self.mmap.write().unwrap();
+        let k = u32::from_le_bytes(mmap[8..12].try_into().unwrap());
+        let len = HEADER_SIZE + (1usize << k) * BUCKET_SIZE;
+        for byte in &mut mmap[HEADER_SIZE..len] {
+            *byte = 0;
+        }
+        Self::write_k(&mut mmap, INITIAL_K);
+    }
+
+    /// The number of bytes of the backing file actually in use: the header
+    /// plus the buckets at the table's current size, as opposed to the much
+    /// larger reserved virtual mmap.
+    pub fn used_bytes(&self) -> usize {
+        let k = u32::from_le_bytes(self.mmap.read().unwrap()[8..12].try_into().unwrap());
+        HEADER_SIZE + (1usize << k) * BUCKET_SIZE
+    }
+
+    /// Flush pending writes to disk.
+    pub fn flush(&self) -> Result<(), Error> {
+        self.mmap
+            .read()
+            .unwrap()
+            .flush()
+            .map_err(|err| Error::FileOpen(self.path.clone(), err))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[quickcheck]
+    fn insert_and_lookup(entries: Vec<(Vec<u8>, usize)>) {
+        let tmp = tempfile::tempdir().unwrap();
+        let index = BucketIndex::new(tmp.path().join("index"), 1 << 20).unwrap();
+
+        let mut expected = std::collections::HashMap::new();
+        for (key, offset) in entries {
+            if key.is_empty() {
+                continue;
+            }
+            index.insert(&key, offset).unwrap();
+            expected.insert(key, offset);
+        }
+
+        for (key, offset) in expected {
+            assert!(index.lookup(&key).contains(&offset));
+        }
+    }
+
+    // `insert_and_lookup` above rarely inserts enough distinct keys to
+    // overflow a bucket, so it never actually exercises `grow`. Insert
+    // enough keys that at least one of the 256 initial buckets is forced to
+    // split, growing the on-disk file well past its initial size — this
+    // used to SIGBUS the process before `grow` retained a `File` handle to
+    // `set_len` against.
+    #[test]
+    fn insert_past_initial_capacity_grows_without_crashing() {
+        let tmp = tempfile::tempdir().unwrap();
+        let index = BucketIndex::new(tmp.path().join("index"), 1 << 24).unwrap();
+
+        for i in 0..20_000u32 {
+            let key = i.to_le_bytes();
+            index.insert(&key, i as usize).unwrap();
+        }
+
+        for i in 0..20_000u32 {
+            let key = i.to_le_bytes();
+            assert!(index.lookup(&key).contains(&(i as usize)));
+        }
+    }
+
+    // Growth that would exceed the reserved mmap must surface as an error,
+    // not abort the process via `assert!`.
+    #[test]
+    fn insert_beyond_reserved_map_size_errors() {
+        let tmp = tempfile::tempdir().unwrap();
+        // Large enough to hold the initial table, too small to ever double it.
+        let min_len = HEADER_SIZE + (1usize << INITIAL_K) * BUCKET_SIZE;
+        let index = BucketIndex::new(tmp.path().join("index"), min_len).unwrap();
+
+        let result = (0..20_000u32).try_for_each(|i| {
+            let key = i.to_le_bytes();
+            index.insert(&key, i as usize)
+        });
+
+        assert!(matches!(result, Err(Error::BucketIndexFull { .. })));
+    }
+}
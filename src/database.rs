@@ -1,25 +1,46 @@
 use crate::{
     flatfile::FlatFile, index::Index, seqno::SeqNoIndex, Error, Record, RecordSerializer, SeqNoIter,
 };
-use std::{path::Path, sync::Arc};
+use flate2::{read::GzDecoder, write::GzEncoder, Compression};
+use serde_json::Value;
+use std::{
+    collections::HashSet,
+    io::{BufRead, BufReader, Read},
+    ops::Bound,
+    path::{Path, PathBuf},
+    sync::{Arc, RwLock},
+};
+use tar::{Archive, Builder, Header};
+
+// Records are appended in chunks of this size so a multi-gigabyte NDJSON
+// dump doesn't hold the append lock for its entire duration, while still
+// writing far fewer than one record per lock acquisition.
+const INGEST_BATCH_SIZE: usize = 1024;
 
 // 4 GiB
 pub const DEFAULT_FLATFILE_MAP_SIZE: usize = (1 << 30) * 4;
 // 512 MiB
 pub const DEFAULT_SEQNO_INDEX_MAP_SIZE: usize = (1 << 20) * 512;
+// 64 MiB
+pub const DEFAULT_BUCKET_INDEX_MAP_SIZE: usize = (1 << 20) * 64;
 
 /// Build `Database` instances.
 pub struct DatabaseBuilder {
     flatfile_map_size: usize,
     seqno_index_map_size: usize,
+    bucket_index_map_size: usize,
 }
 
 #[derive(Clone)]
 pub struct Database<R: RecordSerializer + Clone> {
+    path: PathBuf,
     flatfile: Arc<FlatFile>,
     seqno_index: Arc<SeqNoIndex>,
     index: Index<R>,
     serializer: R,
+    // Taken for writing by `append` and for reading by `snapshot`, so a
+    // backup never observes the flatfile and index mid-write.
+    lock: Arc<RwLock<()>>,
 }
 
 impl DatabaseBuilder {
@@ -28,6 +49,7 @@ impl DatabaseBuilder {
         Self {
             flatfile_map_size: DEFAULT_FLATFILE_MAP_SIZE,
             seqno_index_map_size: DEFAULT_SEQNO_INDEX_MAP_SIZE,
+            bucket_index_map_size: DEFAULT_BUCKET_INDEX_MAP_SIZE,
         }
     }
 
@@ -47,6 +69,18 @@ impl DatabaseBuilder {
         }
     }
 
+    /// The size of `mmap` range reserved for the persistent key index. Must
+    /// be raised before opening a database expected to grow past the
+    /// default 64 MiB table, since the index can't grow past its reserved
+    /// map and `open`/`append`/`compact` return `Error::BucketIndexFull`
+    /// instead.
+    pub fn bucket_index_map_size(self, value: usize) -> Self {
+        Self {
+            bucket_index_map_size: value,
+            ..self
+        }
+    }
+
     /// Open the database. Will create it if not exists.
     pub fn open<P, R>(self, path: P, serializer: R) -> Result<Database<R>, Error>
     where
@@ -63,34 +97,109 @@ impl DatabaseBuilder {
             std::fs::create_dir(path).map_err(|err| Error::FileOpen(path.to_path_buf(), err))?;
         }
 
+        // `FlatFile::new` and `SeqNoIndex::new` write `serializer.version()`
+        // into a header the first time the file is created, and otherwise
+        // read back whatever version is already stored there, failing with
+        // `Error::UnsupportedVersion` if it's newer than `serializer`
+        // supports.
         let flatfile_path = path.join("data");
-        let flatfile = Arc::new(FlatFile::new(flatfile_path, self.flatfile_map_size)?);
+        let flatfile = Arc::new(FlatFile::new(
+            flatfile_path,
+            self.flatfile_map_size,
+            serializer.version(),
+        )?);
 
         let seqno_index_path = path.join("seqno");
         let seqno_index = Arc::new(SeqNoIndex::new(
             seqno_index_path,
             self.seqno_index_map_size,
+            serializer.version(),
         )?);
 
-        let index = Index::new(flatfile.clone(), serializer.clone());
+        let index_path = path.join("index");
+        let index = Index::new(
+            flatfile.clone(),
+            serializer.clone(),
+            index_path,
+            self.bucket_index_map_size,
+        )?;
 
         Ok(Database {
+            path: path.to_path_buf(),
             flatfile,
             seqno_index,
             index,
             serializer,
+            lock: Arc::new(RwLock::new(())),
         })
     }
+
+    /// Unpack a `Database::snapshot` archive into `target_dir`, creating it
+    /// if it doesn't exist.
+    pub fn restore<P: AsRef<Path>, Q: AsRef<Path>>(archive: P, target_dir: Q) -> Result<(), Error> {
+        let target_dir = target_dir.as_ref();
+        if !target_dir.exists() {
+            std::fs::create_dir(target_dir)
+                .map_err(|err| Error::FileOpen(target_dir.to_path_buf(), err))?;
+        }
+
+        let archive_path = archive.as_ref();
+        let file = std::fs::File::open(archive_path)
+            .map_err(|err| Error::FileOpen(archive_path.to_path_buf(), err))?;
+        let mut tar = Archive::new(GzDecoder::new(file));
+
+        let mut manifest = None;
+        for entry in tar
+            .entries()
+            .map_err(|err| Error::FileOpen(archive_path.to_path_buf(), err))?
+        {
+            let mut entry = entry.map_err(|err| Error::FileOpen(archive_path.to_path_buf(), err))?;
+            let name = entry
+                .path()
+                .map_err(|err| Error::FileOpen(archive_path.to_path_buf(), err))?
+                .to_path_buf();
+
+            if name == Path::new("manifest") {
+                let mut buf = Vec::new();
+                entry
+                    .read_to_end(&mut buf)
+                    .map_err(|err| Error::FileOpen(archive_path.to_path_buf(), err))?;
+                manifest = Some(parse_manifest(&buf)?);
+                continue;
+            }
+
+            let dest = target_dir.join(&name);
+            entry.unpack(&dest).map_err(|err| Error::FileOpen(dest, err))?;
+        }
+
+        let (data_len, seqno_len, index_len) = manifest.ok_or(Error::InvalidManifest)?;
+        for (name, len) in [("data", data_len), ("seqno", seqno_len), ("index", index_len)] {
+            let path = target_dir.join(name);
+            let file = std::fs::OpenOptions::new()
+                .write(true)
+                .open(&path)
+                .map_err(|err| Error::FileOpen(path.clone(), err))?;
+            file.set_len(len).map_err(|err| Error::FileOpen(path, err))?;
+        }
+
+        Ok(())
+    }
 }
 
 impl<R: RecordSerializer + Clone> Database<R> {
     /// Write an array of records to the database. This function will block if
     /// another write is still in progress.
     pub fn append(&self, records: &[Record]) -> Result<(), Error> {
+        let _guard = self.lock.write().unwrap();
         let initial_size = self.flatfile.len();
 
+        // Reject a key already committed as well as a key repeated within
+        // this same batch -- the latter would otherwise silently orphan
+        // every record but the last with that key, since only one offset
+        // per key survives in the index.
+        let mut batch_keys = HashSet::with_capacity(records.len());
         for record in records.iter() {
-            if self.index.contains(record.key()) {
+            if self.index.contains(record.key()) || !batch_keys.insert(record.key()) {
                 return Err(Error::RecordExists(record.key().to_vec()));
             }
         }
@@ -100,9 +209,20 @@ impl<R: RecordSerializer + Clone> Database<R> {
         let mut seqno_index_update = Vec::with_capacity(records.len());
         let mut offset = initial_size;
 
-        for record in records.iter() {
+        for (i, record) in records.iter().enumerate() {
             seqno_index_update.push(offset as u64);
-            self.index.put(record.key(), offset);
+            if let Err(err) = self.index.put(record.key(), offset) {
+                // `index.put` can now fail (e.g. the bucket index hitting its
+                // reserved mmap size). Undo the index entries this call
+                // already added and drop the flatfile back to its prior
+                // length, so the failed append leaves no partially-indexed,
+                // seqno-less records behind for a retry to trip over.
+                for record in &records[..i] {
+                    self.index.remove(record.key());
+                }
+                self.flatfile.truncate(initial_size)?;
+                return Err(err);
+            }
             offset += self.serializer.size(record);
         }
 
@@ -113,12 +233,14 @@ impl<R: RecordSerializer + Clone> Database<R> {
 
     /// Get a record by its key.
     pub fn get(&self, key: &[u8]) -> Option<Record> {
+        let _guard = self.lock.read().unwrap();
         let offset = self.index.get(key)?;
         self.flatfile.get_record_at_offset(&self.serializer, offset)
     }
 
     /// Get a record by its sequential number.
     pub fn get_by_seqno(&self, seqno: usize) -> Option<Record> {
+        let _guard = self.lock.read().unwrap();
         let offset = self.seqno_index.get_pointer_to_value(seqno)?;
         self.flatfile
             .get_record_at_offset(&self.serializer, offset as usize)
@@ -127,6 +249,7 @@ impl<R: RecordSerializer + Clone> Database<R> {
     /// Iterate records in the order they were added starting form the given
     /// sequential number.
     pub fn iter_from_seqno(&self, seqno: usize) -> Option<SeqNoIter<R>> {
+        let _guard = self.lock.read().unwrap();
         let offset = self.seqno_index.get_pointer_to_value(seqno)? as usize;
         Some(SeqNoIter::new(
             self.flatfile.clone(),
@@ -134,14 +257,256 @@ impl<R: RecordSerializer + Clone> Database<R> {
             offset,
         ))
     }
+
+    /// Iterate records with keys in `(start, end)`, in ascending key order.
+    pub fn range(&self, start: Bound<&[u8]>, end: Bound<&[u8]>) -> impl Iterator<Item = Record> + '_ {
+        let guard = self.lock.read().unwrap();
+        self.index.range(start, end).into_iter().filter_map(move |offset| {
+            let _guard = &guard;
+            self.flatfile.get_record_at_offset(&self.serializer, offset)
+        })
+    }
+
+    /// Iterate every record whose key starts with `prefix`, in ascending key
+    /// order.
+    pub fn prefix(&self, prefix: &[u8]) -> impl Iterator<Item = Record> + '_ {
+        let guard = self.lock.read().unwrap();
+        self.index.prefix(prefix).into_iter().filter_map(move |offset| {
+            let _guard = &guard;
+            self.flatfile.get_record_at_offset(&self.serializer, offset)
+        })
+    }
+
+    /// Remove a record by appending a tombstone for `key` and dropping it
+    /// from the index.
+    pub fn delete(&self, key: &[u8]) -> Result<(), Error> {
+        let _guard = self.lock.write().unwrap();
+        if !self.index.contains(key) {
+            return Err(Error::RecordNotFound(key.to_vec()));
+        }
+
+        let offset = self.flatfile.len();
+        let tombstone = Record::tombstone(key);
+        self.flatfile.append(&self.serializer, &[tombstone])?;
+        self.seqno_index.append(&[offset as u64])?;
+        self.index.remove(key);
+
+        Ok(())
+    }
+
+    /// Rewrite `data` and `seqno` into fresh files holding only live
+    /// records, then atomically swap them in.
+    pub fn compact(&self) -> Result<(), Error> {
+        let _guard = self.lock.write().unwrap();
+        let tmp_dir = self.path.join("compact.tmp");
+        if tmp_dir.exists() {
+            std::fs::remove_dir_all(&tmp_dir).map_err(|err| Error::FileOpen(tmp_dir.clone(), err))?;
+        }
+        std::fs::create_dir(&tmp_dir).map_err(|err| Error::FileOpen(tmp_dir.clone(), err))?;
+
+        // New files are written at the serializer's current version; records
+        // from an older on-disk version are migrated as they're copied over,
+        // so compaction doubles as an upgrade path.
+        let new_flatfile = FlatFile::new(
+            tmp_dir.join("data"),
+            self.flatfile.map_size(),
+            self.serializer.version(),
+        )?;
+        let new_seqno_index = SeqNoIndex::new(
+            tmp_dir.join("seqno"),
+            self.seqno_index.map_size(),
+            self.serializer.version(),
+        )?;
+
+        let source_version = self.flatfile.version();
+        let mut iter = SeqNoIter::new_raw(self.flatfile.clone(), self.serializer.clone(), 0);
+        let mut offset = 0;
+        let mut new_offset = 0;
+
+        while let Some(record) = iter.next() {
+            let size = self.serializer.size(&record);
+            let is_live = !record.is_tombstone() && self.index.get(record.key()) == Some(offset);
+
+            if is_live {
+                let record = self.serializer.migrate(record, source_version);
+                let new_size = self.serializer.size(&record);
+                new_flatfile.append(&self.serializer, &[record])?;
+                new_seqno_index.append(&[new_offset as u64])?;
+                new_offset += new_size;
+            }
+
+            offset += size;
+        }
+
+        new_flatfile.flush()?;
+        new_seqno_index.flush()?;
+
+        let data_path = self.path.join("data");
+        let seqno_path = self.path.join("seqno");
+        std::fs::rename(tmp_dir.join("data"), &data_path)
+            .map_err(|err| Error::FileOpen(data_path.clone(), err))?;
+        std::fs::rename(tmp_dir.join("seqno"), &seqno_path)
+            .map_err(|err| Error::FileOpen(seqno_path.clone(), err))?;
+        std::fs::remove_dir_all(&tmp_dir).ok();
+
+        self.flatfile.reopen(&data_path)?;
+        self.seqno_index.reopen(&seqno_path)?;
+        self.index.rebuild()?;
+
+        Ok(())
+    }
+
+    /// Stream a point-in-time backup of `data`, `seqno`, and the persistent
+    /// index into a gzip-compressed tar archive at `dest`.
+    pub fn snapshot<P: AsRef<Path>>(&self, dest: P) -> Result<(), Error> {
+        let _guard = self.lock.read().unwrap();
+        let dest = dest.as_ref();
+
+        let dest_file =
+            std::fs::File::create(dest).map_err(|err| Error::FileOpen(dest.to_path_buf(), err))?;
+        let mut archive = Builder::new(GzEncoder::new(dest_file, Compression::default()));
+
+        let data_len = self.flatfile.len();
+        let seqno_len = self.seqno_index.len();
+        let index_len = self.index.persistent_len();
+
+        let manifest = format!("data={}\nseqno={}\nindex={}\n", data_len, seqno_len, index_len);
+        append_bytes(&mut archive, "manifest", manifest.as_bytes())?;
+        append_file(&mut archive, "data", &self.path.join("data"), data_len)?;
+        append_file(&mut archive, "seqno", &self.path.join("seqno"), seqno_len)?;
+        append_file(&mut archive, "index", &self.path.join("index"), index_len)?;
+
+        archive
+            .into_inner()
+            .and_then(|encoder| encoder.finish())
+            .map_err(|err| Error::FileOpen(dest.to_path_buf(), err))?;
+
+        Ok(())
+    }
+
+    /// Bulk-load newline-delimited JSON, using `key_field` as each record's
+    /// key. Returns the number of records ingested.
+    pub fn ingest_ndjson<Rd: Read>(&self, reader: Rd, key_field: &str) -> Result<usize, Error> {
+        let mut reader = BufReader::new(reader);
+        let mut line = String::new();
+        let mut batch = Vec::with_capacity(INGEST_BATCH_SIZE);
+        let mut line_no = 0usize;
+        let mut byte_offset = 0usize;
+        let mut ingested = 0usize;
+
+        loop {
+            line.clear();
+            let read = reader
+                .read_line(&mut line)
+                .map_err(|err| Error::FileOpen(PathBuf::from("<ndjson>"), err))?;
+            if read == 0 {
+                break;
+            }
+            line_no += 1;
+
+            let trimmed = line.trim_end_matches(['\n', '\r']);
+            if trimmed.is_empty() {
+                byte_offset += read;
+                continue;
+            }
+
+            let value: Value = serde_json::from_str(trimmed).map_err(|source| {
+                Error::MalformedIngest {
+                    line: line_no,
+                    offset: byte_offset,
+                    source,
+                }
+            })?;
+
+            let key = value
+                .get(key_field)
+                .and_then(|value| value.as_str())
+                .ok_or_else(|| Error::MissingKeyField {
+                    line: line_no,
+                    offset: byte_offset,
+                    field: key_field.to_owned(),
+                })?;
+
+            batch.push(Record::new(key.as_bytes(), trimmed.as_bytes()));
+            byte_offset += read;
+
+            if batch.len() >= INGEST_BATCH_SIZE {
+                self.append(&batch)?;
+                ingested += batch.len();
+                batch.clear();
+            }
+        }
+
+        if !batch.is_empty() {
+            ingested += batch.len();
+            self.append(&batch)?;
+        }
+
+        Ok(ingested)
+    }
+}
+
+fn append_bytes(
+    archive: &mut Builder<GzEncoder<std::fs::File>>,
+    name: &str,
+    data: &[u8],
+) -> Result<(), Error> {
+    let mut header = Header::new_gnu();
+    header.set_size(data.len() as u64);
+    header.set_mode(0o644);
+    header.set_cksum();
+    archive
+        .append_data(&mut header, name, data)
+        .map_err(|err| Error::FileOpen(PathBuf::from(name), err))
+}
+
+fn append_file(
+    archive: &mut Builder<GzEncoder<std::fs::File>>,
+    name: &str,
+    path: &Path,
+    len: usize,
+) -> Result<(), Error> {
+    let file = std::fs::File::open(path).map_err(|err| Error::FileOpen(path.to_path_buf(), err))?;
+    let mut header = Header::new_gnu();
+    header.set_size(len as u64);
+    header.set_mode(0o644);
+    header.set_cksum();
+    archive
+        .append_data(&mut header, name, file.take(len as u64))
+        .map_err(|err| Error::FileOpen(path.to_path_buf(), err))
+}
+
+/// Parse the `data=<n>\nseqno=<n>\nindex=<n>\n` manifest written by
+/// `Database::snapshot`.
+fn parse_manifest(bytes: &[u8]) -> Result<(u64, u64, u64), Error> {
+    let text = std::str::from_utf8(bytes).map_err(|_| Error::InvalidManifest)?;
+    let mut lengths = [None; 3];
+
+    for line in text.lines() {
+        let (name, value) = line.split_once('=').ok_or(Error::InvalidManifest)?;
+        let value: u64 = value.parse().map_err(|_| Error::InvalidManifest)?;
+        let slot = match name {
+            "data" => &mut lengths[0],
+            "seqno" => &mut lengths[1],
+            "index" => &mut lengths[2],
+            _ => return Err(Error::InvalidManifest),
+        };
+        *slot = Some(value);
+    }
+
+    match lengths {
+        [Some(data), Some(seqno), Some(index)] => Ok((data, seqno, index)),
+        _ => Err(Error::InvalidManifest),
+    }
 }
 
 #[cfg(test)]
 mod tests {
     use super::DatabaseBuilder;
     use crate::{
-        serialization::BasicRecordSerializer, testutils::TestData, Record, RecordSerializer,
+        serialization::BasicRecordSerializer, testutils::TestData, Error, Record, RecordSerializer,
     };
+    use std::ops::Bound;
 
     #[quickcheck]
     fn read_write(mut data: Vec<TestData>) {
@@ -193,4 +558,144 @@ mod tests {
         }
         assert_eq!(count, records.len());
     }
+
+    #[test]
+    fn delete_then_compact_drops_tombstoned_and_superseded_records() {
+        let tmp = tempfile::tempdir().unwrap();
+        let db = DatabaseBuilder::new()
+            .open(tmp.path(), BasicRecordSerializer)
+            .unwrap();
+
+        db.append(&[
+            Record::new(b"a", b"1"),
+            Record::new(b"b", b"2"),
+            Record::new(b"c", b"3"),
+        ])
+        .unwrap();
+        db.delete(b"b").unwrap();
+
+        db.compact().unwrap();
+
+        assert!(db.get(b"a").is_some());
+        assert!(db.get(b"b").is_none());
+        assert!(db.get(b"c").is_some());
+
+        // Compaction rewrote `data`/`seqno` from scratch, so only the two
+        // live records should remain in seqno order.
+        let mut iter = db.iter_from_seqno(0).unwrap();
+        let mut keys = Vec::new();
+        while let Some(record) = iter.next() {
+            keys.push(record.key().to_vec());
+        }
+        assert_eq!(keys, vec![b"a".to_vec(), b"c".to_vec()]);
+    }
+
+    #[test]
+    fn reopen_recovers_from_persistent_index_without_rescanning() {
+        let tmp = tempfile::tempdir().unwrap();
+
+        {
+            let db = DatabaseBuilder::new()
+                .open(tmp.path(), BasicRecordSerializer)
+                .unwrap();
+            db.append(&[Record::new(b"a", b"1"), Record::new(b"b", b"2")])
+                .unwrap();
+            db.delete(b"a").unwrap();
+        }
+
+        let db = DatabaseBuilder::new()
+            .open(tmp.path(), BasicRecordSerializer)
+            .unwrap();
+
+        assert!(db.get(b"a").is_none());
+        assert_eq!(db.get(b"b").unwrap().value(), b"2");
+    }
+
+    #[test]
+    fn snapshot_then_restore_round_trip() {
+        let tmp = tempfile::tempdir().unwrap();
+        let source_dir = tmp.path().join("source");
+        std::fs::create_dir(&source_dir).unwrap();
+        let db = DatabaseBuilder::new()
+            .open(&source_dir, BasicRecordSerializer)
+            .unwrap();
+
+        db.append(&[Record::new(b"a", b"1"), Record::new(b"b", b"2")])
+            .unwrap();
+
+        let archive = tmp.path().join("backup.tar.gz");
+        db.snapshot(&archive).unwrap();
+
+        let restored_dir = tmp.path().join("restored");
+        DatabaseBuilder::restore(&archive, &restored_dir).unwrap();
+
+        let restored = DatabaseBuilder::new()
+            .open(&restored_dir, BasicRecordSerializer)
+            .unwrap();
+
+        assert_eq!(restored.get(b"a").unwrap().value(), b"1");
+        assert_eq!(restored.get(b"b").unwrap().value(), b"2");
+    }
+
+    #[test]
+    fn range_and_prefix_scan_sorted_keys() {
+        let tmp = tempfile::tempdir().unwrap();
+        let db = DatabaseBuilder::new()
+            .open(tmp.path(), BasicRecordSerializer)
+            .unwrap();
+
+        db.append(&[
+            Record::new(b"a/1", b""),
+            Record::new(b"a/2", b""),
+            Record::new(b"b/1", b""),
+            Record::new(b"c/1", b""),
+        ])
+        .unwrap();
+
+        let prefixed: Vec<_> = db.prefix(b"a/").map(|record| record.key().to_vec()).collect();
+        assert_eq!(prefixed, vec![b"a/1".to_vec(), b"a/2".to_vec()]);
+
+        let ranged: Vec<_> = db
+            .range(Bound::Included(b"a/2".as_slice()), Bound::Excluded(b"c/1".as_slice()))
+            .map(|record| record.key().to_vec())
+            .collect();
+        assert_eq!(ranged, vec![b"a/2".to_vec(), b"b/1".to_vec()]);
+    }
+
+    #[test]
+    fn ingest_ndjson_rejects_malformed_lines_and_missing_key_field() {
+        let tmp = tempfile::tempdir().unwrap();
+        let db = DatabaseBuilder::new()
+            .open(tmp.path(), BasicRecordSerializer)
+            .unwrap();
+
+        let malformed = b"{\"id\": \"a\"}\nnot json\n".as_slice();
+        let err = db.ingest_ndjson(malformed, "id").unwrap_err();
+        assert!(matches!(err, Error::MalformedIngest { line: 2, .. }));
+
+        let missing_key = b"{\"id\": \"a\"}\n{\"other\": \"b\"}\n".as_slice();
+        let err = db.ingest_ndjson(missing_key, "id").unwrap_err();
+        assert!(matches!(err, Error::MissingKeyField { line: 2, .. }));
+
+        let valid = b"{\"id\": \"x\"}\n{\"id\": \"y\"}\n".as_slice();
+        let ingested = db.ingest_ndjson(valid, "id").unwrap();
+        assert_eq!(ingested, 2);
+        assert!(db.get(b"x").is_some());
+        assert!(db.get(b"y").is_some());
+    }
+
+    #[test]
+    fn ingest_ndjson_rejects_duplicate_key_within_a_batch() {
+        let tmp = tempfile::tempdir().unwrap();
+        let db = DatabaseBuilder::new()
+            .open(tmp.path(), BasicRecordSerializer)
+            .unwrap();
+
+        let duplicates = b"{\"id\": \"a\"}\n{\"id\": \"a\"}\n".as_slice();
+        let err = db.ingest_ndjson(duplicates, "id").unwrap_err();
+        assert!(matches!(err, Error::RecordExists(key) if key == b"a"));
+
+        // The rejected batch must not have left the first occurrence behind.
+        assert!(db.get(b"a").is_none());
+    }
 }
@@ -1,6 +1,8 @@
-use crate::{flatfile::FlatFile, RecordSerializer, SeqNoIter};
+use crate::{bucket_index::BucketIndex, flatfile::FlatFile, Error, RecordSerializer, SeqNoIter};
 use std::{
     collections::BTreeMap,
+    ops::Bound,
+    path::Path,
     sync::{Arc, RwLock},
 };
 
@@ -9,35 +11,187 @@ pub(crate) struct Index<R: RecordSerializer> {
     data: Arc<FlatFile>,
     serializer: R,
     mapping: Arc<RwLock<BTreeMap<Box<[u8]>, usize>>>,
+    persistent: Arc<BucketIndex>,
 }
 
 impl<R: RecordSerializer + Clone> Index<R> {
-    pub fn new(data: Arc<FlatFile>, serializer: R) -> Self {
-        let mut iter = SeqNoIter::new(data.clone(), serializer.clone(), 0);
+    /// Open the persistent bucket index at `index_path`. When it already
+    /// holds a complete table, the in-memory mapping is rebuilt from it
+    /// directly — one random-access read per entry to recover its key,
+    /// rather than a full sequential replay of the flatfile. Otherwise (no
+    /// index file yet, or one whose header is all zeroes) it falls back to
+    /// replaying every record from the start and persists the result. An
+    /// index file with any other unrecognised header is rejected with
+    /// `Error::InvalidIndex` rather than rebuilt.
+    pub fn new<P: AsRef<Path>>(
+        data: Arc<FlatFile>,
+        serializer: R,
+        index_path: P,
+        bucket_index_map_size: usize,
+    ) -> Result<Self, Error> {
+        let persistent = Arc::new(BucketIndex::new(index_path, bucket_index_map_size)?);
+
+        let mapping = if persistent.is_preexisting() {
+            Self::load_from_persistent(&data, &serializer, &persistent)
+        } else {
+            let mapping = Self::replay(&data, &serializer);
+            for (key, offset) in mapping.iter() {
+                persistent.insert(key, *offset)?;
+            }
+            persistent.flush()?;
+            mapping
+        };
+
+        Ok(Self {
+            data,
+            serializer,
+            mapping: Arc::new(RwLock::new(mapping)),
+            persistent,
+        })
+    }
+
+    /// Recover the in-memory mapping from an already-populated persistent
+    /// index by reading the record at each stored offset to recover its
+    /// key. Skips tombstones left behind by a delete that hasn't been
+    /// compacted away yet.
+    fn load_from_persistent(
+        data: &Arc<FlatFile>,
+        serializer: &R,
+        persistent: &BucketIndex,
+    ) -> BTreeMap<Box<[u8]>, usize> {
+        let mut mapping = BTreeMap::new();
+        for (_, offset) in persistent.entries() {
+            let offset = offset as usize;
+            if let Some(record) = data.get_record_at_offset(serializer, offset) {
+                if !record.is_tombstone() {
+                    mapping.insert(record.key().to_owned().into_boxed_slice(), offset);
+                }
+            }
+        }
+        mapping
+    }
+
+    /// Rescan the flatfile from scratch, applying tombstones as deletes, and
+    /// discard any in-memory and persistent index state built before now.
+    /// Used after `Database::compact` swaps in fresh `data`/`seqno` files.
+    pub fn rebuild(&self) -> Result<(), Error> {
+        let mapping = Self::replay(&self.data, &self.serializer);
+
+        self.persistent.clear();
+        for (key, offset) in mapping.iter() {
+            self.persistent.insert(key, *offset)?;
+        }
+        self.persistent.flush()?;
+
+        *self.mapping.write().unwrap() = mapping;
+
+        Ok(())
+    }
+
+    fn replay(data: &Arc<FlatFile>, serializer: &R) -> BTreeMap<Box<[u8]>, usize> {
+        let mut iter = SeqNoIter::new_raw(data.clone(), serializer.clone(), 0);
         let mut offset = 0;
         let mut mapping = BTreeMap::new();
+
         while let Some(record) = iter.next() {
-            let key = record.key().to_owned().into_boxed_slice();
-            mapping.insert(key, offset);
-            offset += serializer.size(&record);
+            let size = serializer.size(&record);
+            if record.is_tombstone() {
+                mapping.remove(record.key());
+            } else {
+                mapping.insert(record.key().to_owned().into_boxed_slice(), offset);
+            }
+            offset += size;
         }
 
-        let mapping = Arc::new(RwLock::new(mapping));
+        mapping
+    }
+
+    pub fn put(&self, key: &[u8], offset: usize) -> Result<(), Error> {
+        let mut guard = self.mapping.write().unwrap();
+        let previous = guard.insert(key.to_owned().into_boxed_slice(), offset);
+        if let Err(err) = self.persistent.insert(key, offset) {
+            match previous {
+                Some(offset) => guard.insert(key.to_owned().into_boxed_slice(), offset),
+                None => guard.remove(key),
+            };
+            return Err(err);
+        }
+        Ok(())
+    }
 
-        Self {
-            data,
-            serializer,
-            mapping,
+    /// Look up `key`'s offset. Reads go through the persistent bucket index
+    /// rather than the in-memory `mapping`, confirming each hash-matched
+    /// candidate against the flatfile record to rule out collisions — this
+    /// is the "hot" lookup path the bucket index exists to serve.
+    ///
+    /// Note that `mapping` itself is still kept fully populated in RAM,
+    /// because `range`/`prefix` need an ordered structure that a hash table
+    /// can't provide; `get`/`contains` going through `persistent` doesn't
+    /// reduce steady-state memory use on its own.
+    pub fn get(&self, key: &[u8]) -> Option<usize> {
+        for offset in self.persistent.lookup(key) {
+            match self.data.get_record_at_offset(&self.serializer, offset) {
+                Some(record) if record.key() == key && !record.is_tombstone() => {
+                    return Some(offset)
+                }
+                _ => continue,
+            }
         }
+        None
+    }
+
+    pub fn contains(&self, key: &[u8]) -> bool {
+        self.get(key).is_some()
     }
 
-    pub fn put(&self, key: &[u8], offset: usize) {
+    /// Remove `key` from the index, returning its last known offset, if any.
+    pub fn remove(&self, key: &[u8]) -> Option<usize> {
         let mut guard = self.mapping.write().unwrap();
-        guard.insert(key.to_owned().into_boxed_slice(), offset);
+        let offset = guard.remove(key)?;
+        self.persistent.remove(key, offset);
+        Some(offset)
     }
 
-    pub fn get(&self, key: &[u8]) -> Option<usize> {
+    /// The logical size of the persistent bucket index file, for snapshotting.
+    pub fn persistent_len(&self) -> usize {
+        self.persistent.used_bytes()
+    }
+
+    /// Offsets of every record whose key falls within `(start, end)`, in
+    /// ascending key order.
+    pub fn range(&self, start: Bound<&[u8]>, end: Bound<&[u8]>) -> Vec<usize> {
         let guard = self.mapping.read().unwrap();
-        guard.get(key).map(|offset| *offset)
+        guard
+            .range::<[u8], _>((start, end))
+            .map(|(_, offset)| *offset)
+            .collect()
+    }
+
+    /// Offsets of every record whose key starts with `prefix`, in ascending
+    /// key order.
+    pub fn prefix(&self, prefix: &[u8]) -> Vec<usize> {
+        let upper = next_prefix(prefix);
+        let end = match &upper {
+            Some(upper) => Bound::Excluded(upper.as_ref()),
+            None => Bound::Unbounded,
+        };
+        self.range(Bound::Included(prefix), end)
+    }
+}
+
+/// The smallest key that is strictly greater than every key starting with
+/// `prefix`, i.e. `prefix` with its last byte incremented (carrying into
+/// preceding bytes, dropping bytes that overflow). `None` if `prefix` is
+/// all `0xff` bytes, meaning there is no upper bound to exclude.
+fn next_prefix(prefix: &[u8]) -> Option<Box<[u8]>> {
+    let mut upper = prefix.to_vec();
+    while let Some(&last) = upper.last() {
+        if last == 0xff {
+            upper.pop();
+        } else {
+            *upper.last_mut().unwrap() += 1;
+            return Some(upper.into_boxed_slice());
+        }
     }
+    None
 }
\ No newline at end of file
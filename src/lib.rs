@@ -3,9 +3,11 @@
 extern crate quickcheck_macros;
 
 mod appender;
+mod bucket_index;
 mod database;
 mod error;
 mod flatfile;
+mod index;
 mod record;
 mod seqno;
 pub mod serialization;